@@ -1,10 +1,23 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
 
-use axum::{extract::State, http::StatusCode, routing::get, Router};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
 use config::Config;
-use k8s_openapi::api::core::v1::{PersistentVolumeClaim as PVC, Pod};
-use kube::api::{Api, DeleteParams, ListParams};
-use serde::Deserialize;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{Node, PersistentVolume as PV, PersistentVolumeClaim as PVC, Pod};
+use kube::{
+    api::{Api, DeleteParams, ListParams, Preconditions},
+    runtime::{watcher, WatchStreamExt},
+};
+use serde::{Deserialize, Serialize};
 use tower_http::{trace::TraceLayer, validate_request::ValidateRequestHeaderLayer};
 use tracing::*;
 
@@ -19,12 +32,36 @@ struct Settings {
     pub delete_uncontrolled: bool,
     #[serde(default)]
     pub dry_run: bool,
+    // Selects how deletions are triggered: over HTTP or by watching Node transitions
+    #[serde(default)]
+    pub mode: Mode,
+    // Page size used when listing PVCs and pods, to keep memory flat on large clusters
+    #[serde(default = "default_page_size")]
+    pub page_size: u32,
+}
+
+// Trigger mode for the restarter
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    // Wait for an authenticated `GET /delete` to trigger a restart
+    #[default]
+    Http,
+    // Watch `Node` objects and restart stuck pods when a node recovers
+    Watch,
 }
 
 fn default_bind_address() -> SocketAddr {
     "0.0.0.0:3000".parse().unwrap()
 }
 
+fn default_page_size() -> u32 {
+    500
+}
+
+// Delay between the two candidate passes used to guard against creation races.
+const CANDIDATE_RECHECK_DELAY: Duration = Duration::from_secs(2);
+
 // State injected into route handlers
 #[derive(Clone)]
 struct AppState {
@@ -70,8 +107,17 @@ async fn main() -> eyre::Result<()> {
 
     let k8s_client = kube::Client::try_default().await?;
 
+    match settings.mode {
+        Mode::Http => run_http_mode(k8s_client, settings).await,
+        Mode::Watch => run_watch_mode(k8s_client, settings).await,
+    }
+}
+
+// Serve the HTTP API and wait for an authenticated request to trigger a restart.
+async fn run_http_mode(k8s_client: kube::Client, settings: Settings) -> eyre::Result<()> {
     let app = Router::new()
         .route("/delete", get(delete_pods_with_pvc))
+        .route("/preview", get(preview_pods_with_pvc))
         .layer(ValidateRequestHeaderLayer::bearer(&settings.bearer_token))
         .layer(TraceLayer::new_for_http())
         .with_state(AppState {
@@ -84,34 +130,163 @@ async fn main() -> eyre::Result<()> {
     Ok(axum::serve(listner, app).await?)
 }
 
+// Watch `Node` objects and restart stuck pods whenever a node transitions from
+// NotReady back to Ready, which is the classic CSI "volume stuck after reboot" case.
+async fn run_watch_mode(k8s_client: kube::Client, settings: Settings) -> eyre::Result<()> {
+    let nodes_api: Api<Node> = Api::all(k8s_client.clone());
+    // Last observed `Ready` state per node, used to detect NotReady -> Ready transitions
+    let mut ready_state: HashMap<String, bool> = HashMap::new();
+
+    info!("Watching Node objects for recovery transitions");
+    let mut nodes = watcher(nodes_api, watcher::Config::default())
+        .applied_objects()
+        .boxed();
+    while let Some(event) = nodes.next().await {
+        // A transient watch error shouldn't tear down the controller; the watcher restarts
+        // the stream internally, so just log and keep reconciling.
+        let node = match event {
+            Ok(node) => node,
+            Err(err) => {
+                warn!("Node watch error, continuing: {err}");
+                continue;
+            }
+        };
+        let Some(name) = node.metadata.name.clone() else {
+            continue;
+        };
+        let ready = node_is_ready(&node);
+        let was_ready = ready_state.insert(name.clone(), ready);
+        if was_ready == Some(false) && ready {
+            info!("Node {name} recovered (NotReady -> Ready), restarting stuck pods");
+            // A transient apiserver error while acting shouldn't kill the controller; the
+            // next node transition will retry, so log and keep watching.
+            if let Err(err) = restart_stuck_pods_on_node(&k8s_client, &settings, &name).await {
+                warn!("Failed to restart stuck pods on node {name}, continuing: {err:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Select and delete the stuck pods scheduled on a recovered node.
+async fn restart_stuck_pods_on_node(
+    k8s_client: &kube::Client,
+    settings: &Settings,
+    node: &str,
+) -> eyre::Result<()> {
+    let selection = get_confirmed_pod_names(
+        k8s_client.clone(),
+        settings.storage_class.clone(),
+        !settings.delete_uncontrolled,
+        settings.page_size,
+        Some(node),
+    )
+    .await?;
+    info!("Found {} stuck pods on node {node}", selection.pods.len());
+    delete_pods(k8s_client.clone(), selection.pods, settings.dry_run).await?;
+    Ok(())
+}
+
+// Whether a node currently reports its `Ready` condition as `True`.
+fn node_is_ready(node: &Node) -> bool {
+    node.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|cond| cond.type_ == "Ready" && cond.status == "True")
+        })
+        .unwrap_or(false)
+}
+
+// Outcome of the selection half of the pipeline
+struct Selection {
+    // Namespaced paths (`namespace/name`) of PVCs that use a wanted storage class
+    pub pvc_paths: Vec<String>,
+    // Running pods that mount one of those PVCs
+    pub pods: Vec<ObjectPath>,
+}
+
+// Inspectable JSON description of an affected pod, returned by `/preview` and `/delete`.
+#[derive(Debug, Serialize)]
+struct PodCandidate {
+    pub namespace: String,
+    pub name: String,
+    pub node: Option<String>,
+}
+
+// JSON payload describing what a restart matched (and would or did delete).
+#[derive(Debug, Serialize)]
+struct RestartReport {
+    pub storage_classes: Vec<String>,
+    pub pvc_paths: Vec<String>,
+    pub pods: Vec<PodCandidate>,
+}
+
+impl RestartReport {
+    fn new(storage_classes: Vec<String>, pvc_paths: Vec<String>, pods: &[ObjectPath]) -> Self {
+        Self {
+            storage_classes,
+            pvc_paths,
+            pods: pods
+                .iter()
+                .map(|pod| PodCandidate {
+                    namespace: pod.namespace.clone(),
+                    name: pod.name.clone(),
+                    node: pod.node.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
 struct ObjectPath {
     pub namespace: String,
     pub name: String,
+    pub node: Option<String>,
+    // Claim name of the matched PVC, used to confirm it is still `Bound` before deleting
+    pub pvc_name: String,
+    // Identity captured during listing, used as a delete precondition so a concurrently
+    // recreated pod with the same name is never deleted
+    pub uid: Option<String>,
 }
 
 async fn get_pod_names_by_storage_class(
     k8s_client: kube::Client,
     storage_class: Vec<String>,
     skip_uncontrolled: bool,
-) -> eyre::Result<Vec<ObjectPath>> {
-    // Query for all PVCs and filter out those that use required storage class client side
+    page_size: u32,
+    node_name: Option<&str>,
+) -> eyre::Result<Selection> {
+    // Page through all PVCs, keeping only the paths that use a wanted storage class so the
+    // whole cluster's PVCs never sit in memory at once
     let pvcs_api: Api<PVC> = Api::all(k8s_client.clone());
-    let pvcs = pvcs_api.list(&ListParams::default()).await?;
-    let sc_pvc_paths: Vec<_> = pvcs
-        .iter()
-        .filter_map(|pvc| {
-            let sc = pvc.spec.as_ref()?.storage_class_name.as_ref()?;
-            if storage_class.contains(sc) {
-                Some(format!(
-                    "{}/{}",
-                    pvc.metadata.namespace.as_ref()?,
-                    pvc.metadata.name.as_ref()?
-                ))
-            } else {
-                None
+    let mut sc_pvc_paths: Vec<String> = Vec::new();
+    let mut continue_token: Option<String> = None;
+    loop {
+        let mut lp = ListParams::default().limit(page_size);
+        lp.continue_token = continue_token.take();
+        let page = pvcs_api.list(&lp).await?;
+        for pvc in &page {
+            let Some(sc) = pvc.spec.as_ref().and_then(|s| s.storage_class_name.as_ref()) else {
+                continue;
+            };
+            if !storage_class.contains(sc) {
+                continue;
             }
-        })
-        .collect();
+            if let (Some(ns), Some(name)) =
+                (pvc.metadata.namespace.as_ref(), pvc.metadata.name.as_ref())
+            {
+                sc_pvc_paths.push(format!("{ns}/{name}"));
+            }
+        }
+        continue_token = next_continue_token(page.metadata.continue_);
+        if continue_token.is_none() {
+            break;
+        }
+    }
     info!(
         "Found {} PVCs that use one of these storage classes: {:?}",
         sc_pvc_paths.len(),
@@ -119,13 +294,20 @@ async fn get_pod_names_by_storage_class(
     );
     debug!("List of PVCs that use wanted storage class: {sc_pvc_paths:#?}");
 
-    // Query for all pods and filter out those that mount one of previously found PVCs
+    // Page through Running pods, keeping only those that mount one of the matched PVCs
     let pods_api: Api<Pod> = Api::all(k8s_client);
-    let running_selector = &ListParams::default().fields("status.phase==Running");
-    let pods = pods_api.list(running_selector).await?;
-    let pvc_pods: Vec<_> = pods
-        .iter()
-        .filter_map(|pod| {
+    // Let the apiserver do the node filtering when a node is requested
+    let mut field_selector = "status.phase==Running".to_string();
+    if let Some(node) = node_name {
+        field_selector.push_str(&format!(",spec.nodeName=={node}"));
+    }
+    let mut pvc_pods: Vec<ObjectPath> = Vec::new();
+    let mut continue_token: Option<String> = None;
+    loop {
+        let mut lp = ListParams::default().fields(&field_selector).limit(page_size);
+        lp.continue_token = continue_token.take();
+        let page = pods_api.list(&lp).await?;
+        pvc_pods.extend(page.iter().filter_map(|pod| {
             let ns = pod.metadata.namespace.as_ref()?;
             let pod_name = pod.metadata.name.as_ref()?;
             // Exclude pods that do not have any controllers, otherwise it will not be recreated
@@ -141,69 +323,235 @@ async fn get_pod_names_by_storage_class(
                     return Some(ObjectPath {
                         namespace: ns.to_string(),
                         name: pod_name.to_string(),
+                        node: pod.spec.as_ref()?.node_name.clone(),
+                        pvc_name: pvc.claim_name.clone(),
+                        uid: pod.metadata.uid.clone(),
                     });
                 }
             }
             None
-        })
-        .collect();
+        }));
+        continue_token = next_continue_token(page.metadata.continue_);
+        if continue_token.is_none() {
+            break;
+        }
+    }
     info!(
         "Found {} pods that use previously found PVCs",
         pvc_pods.len()
     );
 
-    Ok(pvc_pods)
+    Ok(Selection {
+        pvc_paths: sc_pvc_paths,
+        pods: pvc_pods,
+    })
 }
 
-#[tracing::instrument(skip(state))]
-async fn delete_pods_with_pvc(State(state): State<AppState>) -> Result<(), AppError> {
-    info!(
-        "Querying for pods that use PVCs with one of these storage classes: {:?}",
-        state.settings.storage_class
-    );
-    let pvc_pods = get_pod_names_by_storage_class(
-        state.k8s_client.clone(),
-        state.settings.storage_class,
-        !state.settings.delete_uncontrolled,
+// Normalize a page's `continue` token, treating the empty string (last page) as `None`.
+fn next_continue_token(token: Option<String>) -> Option<String> {
+    token.filter(|t| !t.is_empty())
+}
+
+// Run the selection twice with a short delay and keep only pods present in both passes.
+// A pod mid-creation or mid-deletion rarely survives both passes with the same identity,
+// so this filters out candidates caught in a creation race before we touch them.
+async fn get_confirmed_pod_names(
+    k8s_client: kube::Client,
+    storage_class: Vec<String>,
+    skip_uncontrolled: bool,
+    page_size: u32,
+    node_name: Option<&str>,
+) -> eyre::Result<Selection> {
+    let first = get_pod_names_by_storage_class(
+        k8s_client.clone(),
+        storage_class.clone(),
+        skip_uncontrolled,
+        page_size,
+        node_name,
+    )
+    .await?;
+    tokio::time::sleep(CANDIDATE_RECHECK_DELAY).await;
+    let second = get_pod_names_by_storage_class(
+        k8s_client,
+        storage_class,
+        skip_uncontrolled,
+        page_size,
+        node_name,
     )
     .await?;
 
+    let second_uids: HashSet<&str> = second
+        .pods
+        .iter()
+        .filter_map(|pod| pod.uid.as_deref())
+        .collect();
+    let pods: Vec<_> = first
+        .pods
+        .into_iter()
+        .filter(|pod| pod.uid.as_deref().is_some_and(|uid| second_uids.contains(uid)))
+        .collect();
+    info!(
+        "Confirmed {} pods present in both candidate passes",
+        pods.len()
+    );
+    Ok(Selection {
+        pvc_paths: first.pvc_paths,
+        pods,
+    })
+}
+
+// Shared deletion core: group the selected pods by namespace and delete them, re-checking
+// each pod's PVC binding and identity immediately before issuing the delete.
+async fn delete_pods(
+    k8s_client: kube::Client,
+    pvc_pods: Vec<ObjectPath>,
+    dry_run: bool,
+) -> eyre::Result<Vec<ObjectPath>> {
+    // Pods we actually issued a delete for (or would, under dry_run), for the caller's report
+    let mut acted: Vec<ObjectPath> = Vec::new();
     // Group pods by namespace
-    let mut pvc_pods_by_namespace: HashMap<String, Vec<String>> = HashMap::new();
-    for ObjectPath {
-        namespace,
-        ref name,
-    } in pvc_pods
-    {
+    let mut pvc_pods_by_namespace: HashMap<String, Vec<ObjectPath>> = HashMap::new();
+    for pod in pvc_pods {
         pvc_pods_by_namespace
-            .entry(namespace)
-            .and_modify(|pods| pods.push(name.to_string()))
-            .or_insert(vec![name.to_string()]);
+            .entry(pod.namespace.clone())
+            .or_default()
+            .push(pod);
     }
-    debug!("List of pods that use previously found PVCs: {pvc_pods_by_namespace:#?}");
+    debug!(
+        "List of pods that use previously found PVCs: {:#?}",
+        pvc_pods_by_namespace
+            .values()
+            .flatten()
+            .map(|pod| format!("{}/{}", pod.namespace, pod.name))
+            .collect::<Vec<_>>()
+    );
 
+    let pvs_api: Api<PV> = Api::all(k8s_client.clone());
     for (ns, pod_list) in pvc_pods_by_namespace {
-        let pods_ns_api: Api<Pod> = Api::namespaced(state.k8s_client.clone(), &ns);
-        let dp = DeleteParams {
-            dry_run: state.settings.dry_run,
-            ..Default::default()
-        };
-        for pod in pod_list {
-            match pods_ns_api.delete(&pod, &dp).await? {
+        let pods_ns_api: Api<Pod> = Api::namespaced(k8s_client.clone(), &ns);
+        let pvcs_ns_api: Api<PVC> = Api::namespaced(k8s_client.clone(), &ns);
+        for cand in pod_list {
+            let name = &cand.name;
+            if !pvc_is_bound(&pvcs_ns_api, &pvs_api, &cand.pvc_name).await? {
+                debug!("Skipping {ns}/{name}, PVC {} is not bound yet", cand.pvc_name);
+                continue;
+            }
+            // Re-fetch the pod to make sure it is the same object we listed and still Running
+            let Some(current) = pods_ns_api.get_opt(name).await? else {
+                debug!("Skipping {ns}/{name}, pod no longer exists");
+                continue;
+            };
+            let still_running =
+                current.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Running");
+            // UID identity is enough; resourceVersion churns on every kubelet status update
+            let same_pod = current.metadata.uid == cand.uid;
+            if !still_running || !same_pod {
+                debug!("Skipping {ns}/{name}, pod changed since listing");
+                continue;
+            }
+
+            // Pin the delete to the captured UID so a recreated pod of the same name is spared
+            let dp = DeleteParams {
+                dry_run,
+                preconditions: Some(Preconditions {
+                    uid: cand.uid.clone(),
+                    resource_version: None,
+                }),
+                ..Default::default()
+            };
+            match pods_ns_api.delete(name, &dp).await? {
                 either::Either::Left(_) => {
-                    debug!("Deleting {ns}/{pod}");
+                    debug!("Deleting {ns}/{name}");
+                    acted.push(cand);
                 }
                 either::Either::Right(status) => {
                     if status.is_failure() {
-                        warn!("Failed to delete {ns}/{pod}");
+                        warn!("Failed to delete {ns}/{name}");
                     } else {
-                        debug!("Deleted {ns}/{pod}");
+                        debug!("Deleted {ns}/{name}");
+                        acted.push(cand);
                     }
                 }
             }
         }
     }
 
+    Ok(acted)
+}
+
+// Whether the named PVC is `Bound` and its backing PersistentVolume actually exists.
+async fn pvc_is_bound(
+    pvcs_ns_api: &Api<PVC>,
+    pvs_api: &Api<PV>,
+    pvc_name: &str,
+) -> eyre::Result<bool> {
+    let Some(pvc) = pvcs_ns_api.get_opt(pvc_name).await? else {
+        return Ok(false);
+    };
+    let bound = pvc.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Bound");
+    let Some(pv_name) = pvc.spec.as_ref().and_then(|s| s.volume_name.clone()) else {
+        return Ok(false);
+    };
+    Ok(bound && pvs_api.get_opt(&pv_name).await?.is_some())
+}
+
+// Query parameters accepted by the `/delete` and `/preview` routes.
+#[derive(Debug, Deserialize)]
+struct RestartQuery {
+    // Restrict the restart to Running pods scheduled on this node
+    pub node: Option<String>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn delete_pods_with_pvc(
+    State(state): State<AppState>,
+    Query(query): Query<RestartQuery>,
+) -> Result<Json<RestartReport>, AppError> {
+    info!(
+        "Querying for pods that use PVCs with one of these storage classes: {:?}",
+        state.settings.storage_class
+    );
+    let selection = get_confirmed_pod_names(
+        state.k8s_client.clone(),
+        state.settings.storage_class.clone(),
+        !state.settings.delete_uncontrolled,
+        state.settings.page_size,
+        query.node.as_deref(),
+    )
+    .await?;
+
+    let deleted = delete_pods(state.k8s_client, selection.pods, state.settings.dry_run).await?;
+
     info!("Pods deletion initiated successfully");
-    Ok(())
+    Ok(Json(RestartReport::new(
+        state.settings.storage_class,
+        selection.pvc_paths,
+        &deleted,
+    )))
+}
+
+// Run only the selection half of the pipeline and report what a restart would match.
+#[tracing::instrument(skip(state))]
+async fn preview_pods_with_pvc(
+    State(state): State<AppState>,
+    Query(query): Query<RestartQuery>,
+) -> Result<Json<RestartReport>, AppError> {
+    info!(
+        "Previewing pods that use PVCs with one of these storage classes: {:?}",
+        state.settings.storage_class
+    );
+    let selection = get_pod_names_by_storage_class(
+        state.k8s_client.clone(),
+        state.settings.storage_class.clone(),
+        !state.settings.delete_uncontrolled,
+        state.settings.page_size,
+        query.node.as_deref(),
+    )
+    .await?;
+
+    Ok(Json(RestartReport::new(
+        state.settings.storage_class,
+        selection.pvc_paths,
+        &selection.pods,
+    )))
 }